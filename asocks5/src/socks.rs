@@ -12,12 +12,13 @@ use std::u8;
 
 use byteorder::BigEndian;
 use failure::Fail;
+use sha3::{Digest, Sha3_256};
 
 use crate::consts;
 use crate::consts::Reply;
 use crate::Command;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
 
 #[derive(Debug, Fail)]
@@ -30,6 +31,10 @@ pub enum SocksError {
     InvalidDomainEncoding,
     #[fail(display = "No supported auth methods")]
     NoSupportAuth,
+    #[fail(display = "Authentication failed")]
+    AuthenticationFailed,
+    #[fail(display = "Fragmented UDP datagrams (FRAG={}) are not supported", frag)]
+    FragmentedDatagramNotSupported { frag: u8 },
     #[fail(display = "Unsupported command {}", cmd)]
     CommandUnSupport { cmd: u8 },
     #[fail(display = "Invalid reply {}", reply)]
@@ -59,6 +64,13 @@ pub enum Address {
     DomainNameAddress(String, u16),
 }
 
+/// Prefix mixed into the Tor v3 onion checksum, per rend-spec-v3 section 6.
+const ONION_V3_CHECKSUM_CONST: &[u8] = b".onion checksum";
+/// The only onion service version this crate understands.
+const ONION_V3_VERSION: u8 = 0x03;
+/// Length of the base32 label in a v3 `<56 chars>.onion` hostname.
+const ONION_V3_LABEL_LEN: usize = 56;
+
 impl Address {
     pub fn len(&self) -> usize {
         match *self {
@@ -67,6 +79,88 @@ impl Address {
             Address::DomainNameAddress(ref dmname, _) => 1 + 1 + dmname.len() + 2,
         }
     }
+
+    /// Writes the ATYP + addr + port layout expected on the wire, mirroring
+    /// what `read_socks_address` parses.
+    pub async fn write_to(&self, stream: &mut TcpStream) -> Result<(), SocksError> {
+        match *self {
+            Address::SocketAddress(SocketAddr::V4(ref addr)) => {
+                stream.write_u8(consts::AddrType::IPV4 as u8).await?;
+                stream.write_all(&addr.ip().octets()).await?;
+                stream.write_u16(addr.port()).await?;
+            }
+            Address::SocketAddress(SocketAddr::V6(ref addr)) => {
+                stream.write_u8(consts::AddrType::IPV6 as u8).await?;
+                stream.write_all(&addr.ip().octets()).await?;
+                stream.write_u16(addr.port()).await?;
+            }
+            Address::DomainNameAddress(ref dmname, port) => {
+                stream.write_u8(consts::AddrType::DomainName as u8).await?;
+                stream.write_u8(dmname.len() as u8).await?;
+                stream.write_all(dmname.as_bytes()).await?;
+                stream.write_u16(port).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether this is a domain name address ending in `.onion`.
+    pub fn is_onion(&self) -> bool {
+        match *self {
+            Address::DomainNameAddress(ref name, _) => name.to_ascii_lowercase().ends_with(".onion"),
+            Address::SocketAddress(..) => false,
+        }
+    }
+
+    /// Validates a Tor v3 `.onion` hostname (rend-spec-v3 section 6): the
+    /// 56-char label base32-decodes to `PUBKEY(32) || CHECKSUM(2) ||
+    /// VERSION(1)`, `VERSION` must be `0x03`, and `CHECKSUM` must equal the
+    /// first two bytes of `SHA3-256(".onion checksum" || PUBKEY || VERSION)`.
+    ///
+    /// Returns `SocksError::InvalidDomainEncoding` for anything else,
+    /// including non-onion addresses, so a server can reject malformed
+    /// onion targets before forwarding them to the Tor daemon.
+    pub fn validate_onion(&self) -> Result<(), SocksError> {
+        let name = match *self {
+            Address::DomainNameAddress(ref name, _) => name,
+            Address::SocketAddress(..) => return Err(SocksError::InvalidDomainEncoding),
+        };
+
+        let lower = name.to_ascii_lowercase();
+        let label = lower
+            .strip_suffix(".onion")
+            .ok_or(SocksError::InvalidDomainEncoding)?;
+        if label.len() != ONION_V3_LABEL_LEN {
+            return Err(SocksError::InvalidDomainEncoding);
+        }
+
+        let decoded = base32::decode(
+            base32::Alphabet::RFC4648 { padding: false },
+            &label.to_ascii_uppercase(),
+        )
+        .ok_or(SocksError::InvalidDomainEncoding)?;
+        if decoded.len() != 35 {
+            return Err(SocksError::InvalidDomainEncoding);
+        }
+
+        let (pubkey, rest) = decoded.split_at(32);
+        let (checksum, version) = (&rest[..2], rest[2]);
+        if version != ONION_V3_VERSION {
+            return Err(SocksError::InvalidDomainEncoding);
+        }
+
+        let mut hasher = Sha3_256::new();
+        hasher.update(ONION_V3_CHECKSUM_CONST);
+        hasher.update(pubkey);
+        hasher.update(&[version]);
+        let digest = hasher.finalize();
+
+        if &digest[..2] != checksum {
+            return Err(SocksError::InvalidDomainEncoding);
+        }
+
+        Ok(())
+    }
 }
 
 impl Debug for Address {
@@ -172,6 +266,21 @@ pub struct TcpRequestHeader {
     pub address: Address,
 }
 
+impl TcpRequestHeader {
+    /// Creates a request header
+    pub fn new(command: Command, address: Address) -> TcpRequestHeader {
+        TcpRequestHeader { command, address }
+    }
+
+    /// Writes to a writer
+    pub async fn write_to(&self, stream: &mut TcpStream) -> Result<(), SocksError> {
+        stream.write_u8(consts::SOCKS5_VERSION).await?;
+        stream.write_u8(self.command.as_u8()).await?;
+        stream.write_u8(0x00).await?;
+        self.address.write_to(stream).await
+    }
+}
+
 /// TCP response header
 ///
 /// ```plain
@@ -200,6 +309,29 @@ impl TcpResponseHeader {
     pub fn len(&self) -> usize {
         self.address.len() + 3
     }
+
+    /// Writes to a writer
+    pub async fn write_to(&self, stream: &mut TcpStream) -> Result<(), SocksError> {
+        stream.write_u8(consts::SOCKS5_VERSION).await?;
+        stream.write_u8(self.reply.as_u8()).await?;
+        stream.write_u8(0x00).await?;
+        self.address.write_to(stream).await
+    }
+}
+
+/// Read a `TcpResponseHeader` from a reader
+pub async fn read_tcp_response_header(
+    mut stream: &mut TcpStream,
+) -> Result<TcpResponseHeader, SocksError> {
+    let mut buf = [0u8; 3];
+    stream.read_exact(&mut buf).await?;
+    let ver = buf[0];
+    if ver != consts::SOCKS5_VERSION {
+        return Err(SocksError::SocksVersionNoSupport { ver });
+    }
+    let reply = Reply::from_reply_code(buf[1]);
+    let address = read_socks_address(&mut stream).await?;
+    Ok(TcpResponseHeader { reply, address })
 }
 
 /// SOCKS5 handshake request packet
@@ -216,6 +348,21 @@ pub struct HandshakeRequest {
     pub methods: Vec<u8>,
 }
 
+impl HandshakeRequest {
+    /// Creates a handshake request
+    pub fn new(methods: Vec<u8>) -> HandshakeRequest {
+        HandshakeRequest { methods }
+    }
+
+    /// Writes to a writer
+    pub async fn write_to(&self, stream: &mut TcpStream) -> Result<(), SocksError> {
+        stream.write_u8(consts::SOCKS5_VERSION).await?;
+        stream.write_u8(self.methods.len() as u8).await?;
+        stream.write_all(&self.methods).await?;
+        Ok(())
+    }
+}
+
 /// Read from a reader
 pub async fn read_handshake_request(mut s: &mut TcpStream) -> Result<HandshakeRequest, SocksError> {
     let mut buf = [0u8, 0u8];
@@ -246,6 +393,539 @@ pub struct HandshakeResponse {
     pub chosen_method: u8,
 }
 
+impl HandshakeResponse {
+    /// Creates a handshake response
+    pub fn new(chosen_method: u8) -> HandshakeResponse {
+        HandshakeResponse { chosen_method }
+    }
+
+    /// Writes to a writer
+    pub async fn write_to(&self, stream: &mut TcpStream) -> Result<(), SocksError> {
+        stream.write_u8(consts::SOCKS5_VERSION).await?;
+        stream.write_u8(self.chosen_method).await?;
+        Ok(())
+    }
+}
+
+/// Read a `HandshakeResponse` from a reader
+pub async fn read_handshake_response(
+    stream: &mut TcpStream,
+) -> Result<HandshakeResponse, SocksError> {
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+    let ver = buf[0];
+    if ver != consts::SOCKS5_VERSION {
+        stream.shutdown().await?;
+        return Err(SocksError::SocksVersionNoSupport { ver });
+    }
+    Ok(HandshakeResponse {
+        chosen_method: buf[1],
+    })
+}
+
+/// Performs the client side of a SOCKS5 CONNECT handshake over an already
+/// established TCP connection to the proxy.
+///
+/// Sends a no-auth `HandshakeRequest`, reads back the `HandshakeResponse`,
+/// then sends a `TcpRequestHeader` with `Command::Connect` for `target` and
+/// parses the `TcpResponseHeader` reply. `target` is written verbatim (so a
+/// `Address::DomainNameAddress` is resolved by the proxy, not locally) which
+/// is what lets this be used to tunnel through e.g. a local Tor SOCKS5 proxy.
+///
+/// Returns the bound address reported by the proxy on success.
+pub async fn socks_connect(
+    stream: &mut TcpStream,
+    target: Address,
+) -> Result<Address, SocksError> {
+    let handshake_req = HandshakeRequest::new(vec![consts::SOCKS5_AUTH_METHOD_NONE]);
+    handshake_req.write_to(stream).await?;
+
+    let handshake_resp = read_handshake_response(stream).await?;
+    if handshake_resp.chosen_method != consts::SOCKS5_AUTH_METHOD_NONE {
+        stream.shutdown().await?;
+        return Err(SocksError::NoSupportAuth);
+    }
+
+    let req = TcpRequestHeader::new(Command::Connect, target);
+    req.write_to(stream).await?;
+
+    let resp = read_tcp_response_header(stream).await?;
+    if resp.reply != Reply::Succeeded {
+        stream.shutdown().await?;
+        return Err(SocksError::RepliedError { reply: resp.reply });
+    }
+
+    Ok(resp.address)
+}
+
+/// Drives the RFC1928 BIND flow for FTP-style reverse connections: binds a
+/// listening socket near `requested`, sends the first `TcpResponseHeader`
+/// advertising the address the proxy is now listening on, waits for the
+/// single inbound connection, then sends the second `TcpResponseHeader`
+/// carrying the peer's address once it connects.
+pub async fn socks_bind(
+    stream: &mut TcpStream,
+    requested: Address,
+) -> Result<(TcpStream, Address), SocksError> {
+    let bind_addr = match requested {
+        Address::SocketAddress(ref addr) => SocketAddr::new(addr.ip(), 0),
+        Address::DomainNameAddress(..) => SocketAddr::from(([0, 0, 0, 0], 0)),
+    };
+
+    let listener = TcpListener::bind(bind_addr).await?;
+    let bound_addr = Address::SocketAddress(listener.local_addr()?);
+    TcpResponseHeader::new(Reply::Succeeded, bound_addr)
+        .write_to(stream)
+        .await?;
+
+    let (peer_stream, peer_addr) = listener.accept().await?;
+    let peer_addr = Address::SocketAddress(peer_addr);
+    TcpResponseHeader::new(Reply::Succeeded, peer_addr.clone())
+        .write_to(stream)
+        .await?;
+
+    Ok((peer_stream, peer_addr))
+}
+
+/// Credential store consulted during RFC1929 username/password
+/// sub-negotiation. Implemented for any `Fn(&str, &str) -> bool` so a server
+/// can plug in a closure, or implement it on a custom type backed by a real
+/// credential store.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, username: &str, password: &str) -> bool;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(&str, &str) -> bool + Send + Sync,
+{
+    fn authenticate(&self, username: &str, password: &str) -> bool {
+        self(username, password)
+    }
+}
+
+/// RFC1929 username/password authentication request
+///
+/// ```plain
+/// +----+------+----------+------+----------+
+/// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+/// +----+------+----------+------+----------+
+/// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+/// +----+------+----------+------+----------+
+/// ```
+#[derive(Clone, Debug)]
+pub struct PasswordAuthRequest {
+    pub username: String,
+    pub password: String,
+}
+
+impl PasswordAuthRequest {
+    /// Writes to a writer
+    pub async fn write_to(&self, stream: &mut TcpStream) -> Result<(), SocksError> {
+        stream.write_u8(consts::SOCKS5_PASSWORD_AUTH_VERSION).await?;
+        stream.write_u8(self.username.len() as u8).await?;
+        stream.write_all(self.username.as_bytes()).await?;
+        stream.write_u8(self.password.len() as u8).await?;
+        stream.write_all(self.password.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+/// Read a `PasswordAuthRequest` from a reader
+pub async fn read_password_auth_request(
+    stream: &mut TcpStream,
+) -> Result<PasswordAuthRequest, SocksError> {
+    let mut b = [0u8; 1];
+    stream.read_exact(&mut b).await?;
+    let ver = b[0];
+    if ver != consts::SOCKS5_PASSWORD_AUTH_VERSION {
+        stream.shutdown().await?;
+        return Err(SocksError::InvalidData {
+            msg: "unsupported password auth version",
+            data: vec![ver],
+        });
+    }
+
+    stream.read_exact(&mut b).await?;
+    let mut uname = vec![0u8; b[0] as usize];
+    stream.read_exact(&mut uname).await?;
+
+    stream.read_exact(&mut b).await?;
+    let mut passwd = vec![0u8; b[0] as usize];
+    stream.read_exact(&mut passwd).await?;
+
+    Ok(PasswordAuthRequest {
+        username: String::from_utf8_lossy(&uname).into_owned(),
+        password: String::from_utf8_lossy(&passwd).into_owned(),
+    })
+}
+
+/// RFC1929 username/password authentication reply
+///
+/// ```plain
+/// +----+--------+
+/// |VER | STATUS |
+/// +----+--------+
+/// | 1  |   1    |
+/// +----+--------+
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct PasswordAuthResponse {
+    pub status: u8,
+}
+
+impl PasswordAuthResponse {
+    pub fn new(status: u8) -> PasswordAuthResponse {
+        PasswordAuthResponse { status }
+    }
+
+    /// Writes to a writer
+    pub async fn write_to(&self, stream: &mut TcpStream) -> Result<(), SocksError> {
+        stream.write_u8(consts::SOCKS5_PASSWORD_AUTH_VERSION).await?;
+        stream.write_u8(self.status).await?;
+        Ok(())
+    }
+}
+
+/// Selects an auth method from a client's `HandshakeRequest`, replies with
+/// the chosen `HandshakeResponse`, and drives the RFC1929 sub-negotiation
+/// when username/password is chosen.
+///
+/// Prefers no-auth (`0x00`) when the client offers it; otherwise falls back
+/// to username/password (`0x02`) if `authenticator` is provided and the
+/// client offers it too. If neither is possible, replies `0xFF` and returns
+/// `SocksError::NoSupportAuth`.
+pub async fn negotiate_auth_method(
+    stream: &mut TcpStream,
+    handshake: &HandshakeRequest,
+    authenticator: Option<&dyn Authenticator>,
+) -> Result<(), SocksError> {
+    let chosen = if handshake
+        .methods
+        .contains(&consts::SOCKS5_AUTH_METHOD_NONE)
+    {
+        consts::SOCKS5_AUTH_METHOD_NONE
+    } else if authenticator.is_some()
+        && handshake
+            .methods
+            .contains(&consts::SOCKS5_AUTH_METHOD_PASSWORD)
+    {
+        consts::SOCKS5_AUTH_METHOD_PASSWORD
+    } else {
+        HandshakeResponse::new(0xff).write_to(stream).await?;
+        stream.shutdown().await?;
+        return Err(SocksError::NoSupportAuth);
+    };
+
+    HandshakeResponse::new(chosen).write_to(stream).await?;
+
+    if chosen == consts::SOCKS5_AUTH_METHOD_PASSWORD {
+        let req = read_password_auth_request(stream).await?;
+        let authenticator = authenticator.expect("password method only chosen when present");
+        let ok = authenticator.authenticate(&req.username, &req.password);
+
+        PasswordAuthResponse::new(if ok { 0x00 } else { 0x01 })
+            .write_to(stream)
+            .await?;
+
+        if !ok {
+            stream.shutdown().await?;
+            return Err(SocksError::AuthenticationFailed);
+        }
+    }
+
+    Ok(())
+}
+
+/// Encodes the header of a SOCKS5 UDP relay datagram (RFC1928)
+///
+/// ```plain
+/// +----+------+------+----------+----------+----------+
+/// |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+/// +----+------+------+----------+----------+----------+
+/// | 2  |  1   |  1   | Variable |    2     | Variable |
+/// +----+------+------+----------+----------+----------+
+/// ```
+///
+/// `frag` is written as given; `decode_udp_packet` rejects any non-zero
+/// `FRAG`, as reassembly of fragmented datagrams is not implemented.
+pub fn encode_udp_header(addr: &Address, frag: u8, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&[0x00, 0x00]); // RSV
+    buf.push(frag);
+    match *addr {
+        Address::SocketAddress(SocketAddr::V4(ref addr)) => {
+            buf.push(consts::AddrType::IPV4 as u8);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        Address::SocketAddress(SocketAddr::V6(ref addr)) => {
+            buf.push(consts::AddrType::IPV6 as u8);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        Address::DomainNameAddress(ref dmname, port) => {
+            buf.push(consts::AddrType::DomainName as u8);
+            buf.push(dmname.len() as u8);
+            buf.extend_from_slice(dmname.as_bytes());
+            buf.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+}
+
+/// Decodes a SOCKS5 UDP relay datagram, returning the destination address,
+/// the fragment number, and the remaining payload slice.
+///
+/// Rejects any datagram with a non-zero `FRAG`, since most clients only ever
+/// send unfragmented datagrams and this crate does not reassemble fragments.
+pub fn decode_udp_packet(buf: &[u8]) -> Result<(Address, u8, &[u8]), SocksError> {
+    if buf.len() < 4 {
+        return Err(SocksError::InvalidData {
+            msg: "UDP datagram too short",
+            data: buf.to_vec(),
+        });
+    }
+
+    let frag = buf[2];
+    if frag != 0 {
+        return Err(SocksError::FragmentedDatagramNotSupported { frag });
+    }
+
+    let addr_type: consts::AddrType = buf[3]
+        .try_into()
+        .map_err(|_| SocksError::AddressTypeNotSupported { code: buf[3] })?;
+
+    let mut cursor = io::Cursor::new(&buf[4..]);
+    let addr = match addr_type {
+        consts::AddrType::IPV4 => {
+            let mut raw = [0u8; 4];
+            io::Read::read_exact(&mut cursor, &mut raw)?;
+            let v4addr = Ipv4Addr::from(raw);
+            let port = cursor.read_u16be()?;
+            Address::SocketAddress(SocketAddr::V4(SocketAddrV4::new(v4addr, port)))
+        }
+        consts::AddrType::IPV6 => {
+            let mut raw = [0u8; 16];
+            io::Read::read_exact(&mut cursor, &mut raw)?;
+            let v6addr = Ipv6Addr::from(raw);
+            let port = cursor.read_u16be()?;
+            Address::SocketAddress(SocketAddr::V6(SocketAddrV6::new(v6addr, port, 0, 0)))
+        }
+        consts::AddrType::DomainName => {
+            let mut len_buf = [0u8; 1];
+            io::Read::read_exact(&mut cursor, &mut len_buf)?;
+            let addr_len = len_buf[0] as usize;
+            let mut raw = vec![0u8; addr_len];
+            io::Read::read_exact(&mut cursor, &mut raw)?;
+            let port = cursor.read_u16be()?;
+            Address::DomainNameAddress(String::from_utf8_lossy(&raw).into_owned(), port)
+        }
+    };
+
+    let consumed = 4 + cursor.position() as usize;
+    Ok((addr, frag, &buf[consumed..]))
+}
+
+/// SOCKS4/4a request header
+///
+/// ```plain
+/// +----+----+----+----+----+----+----+----+----+----+....+----+
+/// | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+/// +----+----+----+----+----+----+----+----+----+----+....+----+
+/// | 1  | 1  |    2    |         4         | variable     |  1 |
+/// +----+----+----+----+----+----+----+----+----+----+....+----+
+/// ```
+///
+/// SOCKS4a is signalled by a `DSTIP` of `0.0.0.x` (`x != 0`): the real IP is
+/// unknown to the client, and a NUL-terminated hostname follows `USERID`.
+#[derive(Clone, Debug)]
+pub struct Socks4RequestHeader {
+    pub command: Command,
+    pub address: Address,
+    pub user_id: Vec<u8>,
+}
+
+async fn read_until_nul(stream: &mut TcpStream) -> Result<Vec<u8>, SocksError> {
+    let mut out = Vec::new();
+    let mut b = [0u8; 1];
+    loop {
+        stream.read_exact(&mut b).await?;
+        if b[0] == 0 {
+            break;
+        }
+        out.push(b[0]);
+    }
+    Ok(out)
+}
+
+/// Read a `Socks4RequestHeader` from a reader
+pub async fn read_socks4_request_header(
+    stream: &mut TcpStream,
+) -> Result<Socks4RequestHeader, SocksError> {
+    let mut buf = [0u8; 1 + 1 + 2 + 4];
+    stream.read_exact(&mut buf).await?;
+
+    let ver = buf[0];
+    if ver != consts::SOCKS4_VERSION {
+        stream.shutdown().await?;
+        return Err(SocksError::SocksVersionNoSupport { ver });
+    }
+
+    let cmd = buf[1];
+    let command = match cmd {
+        0x01 => Command::Connect,
+        0x02 => Command::Bind,
+        _ => return Err(SocksError::CommandUnSupport { cmd }),
+    };
+
+    let mut cursor = io::Cursor::new(&buf[2..4]);
+    let port = cursor.read_u16be()?;
+    let ip_octets = [buf[4], buf[5], buf[6], buf[7]];
+
+    let user_id = read_until_nul(stream).await?;
+
+    // SOCKS4a sentinel: first three octets zero, last one non-zero.
+    let is_socks4a =
+        ip_octets[0] == 0 && ip_octets[1] == 0 && ip_octets[2] == 0 && ip_octets[3] != 0;
+    let address = if is_socks4a {
+        let host = read_until_nul(stream).await?;
+        Address::DomainNameAddress(String::from_utf8_lossy(&host).into_owned(), port)
+    } else {
+        let v4addr = Ipv4Addr::from(ip_octets);
+        Address::SocketAddress(SocketAddr::V4(SocketAddrV4::new(v4addr, port)))
+    };
+
+    Ok(Socks4RequestHeader {
+        command,
+        address,
+        user_id,
+    })
+}
+
+/// SOCKS4 reply status
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Socks4Reply {
+    /// Request granted (`CD = 0x5A`)
+    Granted,
+    /// Request rejected or failed (`CD = 0x5B`)
+    Rejected,
+}
+
+impl Socks4Reply {
+    fn code(self) -> u8 {
+        match self {
+            Socks4Reply::Granted => 0x5a,
+            Socks4Reply::Rejected => 0x5b,
+        }
+    }
+}
+
+/// SOCKS4 reply header
+///
+/// ```plain
+/// +----+----+----+----+----+----+----+----+
+/// | VN | CD | DSTPORT |      DSTIP        |
+/// +----+----+----+----+----+----+----+----+
+/// | 1  | 1  |    2    |         4         |
+/// +----+----+----+----+----+----+----+----+
+/// ```
+#[derive(Clone, Debug)]
+pub struct Socks4ResponseHeader {
+    pub reply: Socks4Reply,
+    pub address: SocketAddrV4,
+}
+
+impl Socks4ResponseHeader {
+    pub fn new(reply: Socks4Reply, address: SocketAddrV4) -> Socks4ResponseHeader {
+        Socks4ResponseHeader { reply, address }
+    }
+
+    /// Writes to a writer
+    pub async fn write_to(&self, stream: &mut TcpStream) -> Result<(), SocksError> {
+        stream.write_u8(0x00).await?; // VN is always 0x00 in the reply
+        stream.write_u8(self.reply.code()).await?;
+        stream.write_u16(self.address.port()).await?;
+        stream.write_all(&self.address.ip().octets()).await?;
+        Ok(())
+    }
+}
+
+/// SOCKS protocol version, detected from the first byte of a new connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocksVersion {
+    V4,
+    V5,
+}
+
+/// Peeks the first byte of a new connection to determine whether the client
+/// is speaking SOCKS4(a) or SOCKS5, without consuming it, so the caller can
+/// dispatch to `read_socks4_request_header` or the SOCKS5 handshake.
+pub async fn peek_socks_version(stream: &TcpStream) -> Result<SocksVersion, SocksError> {
+    let mut buf = [0u8; 1];
+    stream.peek(&mut buf).await?;
+    match buf[0] {
+        consts::SOCKS4_VERSION => Ok(SocksVersion::V4),
+        consts::SOCKS5_VERSION => Ok(SocksVersion::V5),
+        ver => Err(SocksError::SocksVersionNoSupport { ver }),
+    }
+}
+
+impl Reply {
+    /// Maps a raw RFC1928 reply code to a `Reply`, treating any code outside
+    /// the ones defined by the RFC as `Reply::OtherReply` rather than
+    /// failing.
+    pub fn from_reply_code(code: u8) -> Reply {
+        match code {
+            0x00 => Reply::Succeeded,
+            0x01 => Reply::GeneralFailure,
+            0x02 => Reply::ConnectionNotAllowed,
+            0x03 => Reply::NetworkUnreachable,
+            0x04 => Reply::HostUnreachable,
+            0x05 => Reply::ConnectionRefused,
+            0x06 => Reply::TtlExpired,
+            0x07 => Reply::CommandNotSupported,
+            0x08 => Reply::AddressTypeNotSupported,
+            _ => Reply::OtherReply(code),
+        }
+    }
+
+    /// Maps this reply to the closest `std::io::ErrorKind`, so clients using
+    /// `socks_connect` get an idiomatic `io::Error` rather than having to
+    /// match on the opaque `SocksError::RepliedError`.
+    pub fn to_io_error_kind(self) -> io::ErrorKind {
+        match self {
+            Reply::Succeeded => io::ErrorKind::Other,
+            Reply::GeneralFailure => io::ErrorKind::Other,
+            Reply::ConnectionNotAllowed => io::ErrorKind::PermissionDenied,
+            // `ErrorKind::NetworkUnreachable`/`HostUnreachable`/`Unsupported` are
+            // not available on this crate's MSRV (pre-dates their 1.83
+            // stabilization), so these fall back to `Other`.
+            Reply::NetworkUnreachable => io::ErrorKind::Other,
+            Reply::HostUnreachable => io::ErrorKind::Other,
+            Reply::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+            Reply::TtlExpired => io::ErrorKind::TimedOut,
+            Reply::CommandNotSupported => io::ErrorKind::Other,
+            Reply::AddressTypeNotSupported => io::ErrorKind::Other,
+            Reply::OtherReply(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl convert::From<Reply> for io::Error {
+    fn from(reply: Reply) -> io::Error {
+        io::Error::from(reply.to_io_error_kind())
+    }
+}
+
+impl convert::From<SocksError> for io::Error {
+    fn from(err: SocksError) -> io::Error {
+        match err {
+            SocksError::RepliedError { reply } => reply.into(),
+            SocksError::IOError { err } => err,
+            other => io::Error::new(io::ErrorKind::Other, other.to_string()),
+        }
+    }
+}
+
 pub(crate) trait CursorRead {
     fn read_u16be(&mut self) -> Result<u16, io::Error>;
     fn read_u32be(&mut self) -> Result<u32, io::Error>;
@@ -261,3 +941,97 @@ impl<T: AsRef<[u8]>> CursorRead for io::Cursor<T> {
         self.read_u32::<BigEndian>()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn udp_header_round_trips_through_encode_and_decode() {
+        let addr = Address::DomainNameAddress("example.net".to_owned(), 80);
+        let mut buf = Vec::new();
+        encode_udp_header(&addr, 0, &mut buf);
+        buf.extend_from_slice(b"payload");
+
+        let (decoded_addr, frag, payload) = decode_udp_packet(&buf).unwrap();
+        assert_eq!(decoded_addr, addr);
+        assert_eq!(frag, 0);
+        assert_eq!(payload, b"payload");
+    }
+
+    #[test]
+    fn udp_header_rejects_fragmented_datagrams() {
+        let addr = Address::SocketAddress(SocketAddr::from(([127, 0, 0, 1], 8080)));
+        let mut buf = Vec::new();
+        encode_udp_header(&addr, 1, &mut buf);
+
+        match decode_udp_packet(&buf) {
+            Err(SocksError::FragmentedDatagramNotSupported { frag: 1 }) => {}
+            other => panic!("expected FragmentedDatagramNotSupported, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn socks4a_request_decodes_domain_name() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(&[0x04, 0x01, 0x00, 0x50, 0x00, 0x00, 0x00, 0x01])
+                .await
+                .unwrap();
+            stream.write_all(b"user\0").await.unwrap();
+            stream.write_all(b"example.net\0").await.unwrap();
+        });
+
+        let (mut server_stream, _) = listener.accept().await.unwrap();
+        let header = read_socks4_request_header(&mut server_stream).await.unwrap();
+
+        assert!(matches!(header.command, Command::Connect));
+        assert_eq!(
+            header.address,
+            Address::DomainNameAddress("example.net".to_owned(), 80)
+        );
+        assert_eq!(header.user_id, b"user");
+
+        client.await.unwrap();
+    }
+
+    #[test]
+    fn validate_onion_accepts_known_good_v3_address() {
+        // The real facebookwkhpilnemxj7asaniu7vnjjbiltxjqhye3mhbshg7kx5tfyd.onion,
+        // a publicly documented v3 onion service, used as a known-good vector.
+        let addr = Address::DomainNameAddress(
+            "facebookwkhpilnemxj7asaniu7vnjjbiltxjqhye3mhbshg7kx5tfyd.onion".to_owned(),
+            0,
+        );
+        assert!(addr.is_onion());
+        addr.validate_onion()
+            .expect("known-good v3 onion address should validate");
+    }
+
+    #[test]
+    fn validate_onion_is_case_insensitive_and_rejects_bad_checksum() {
+        let mixed_case = Address::DomainNameAddress(
+            "facebookwkhpilnemxj7asaniu7vnjjbiltxjqhye3mhbshg7kx5tfyd.Onion".to_owned(),
+            0,
+        );
+        assert!(mixed_case.is_onion());
+        mixed_case
+            .validate_onion()
+            .expect("validate_onion should agree with is_onion on mixed case");
+
+        // Same pubkey and version byte as above; one character of the
+        // checksum part of the label is changed.
+        let bad_checksum = Address::DomainNameAddress(
+            "facebookwkhpilnemxj7asaniu7vnjjbiltxjqhye3mhbshg7kx5tayd.onion".to_owned(),
+            0,
+        );
+        assert!(matches!(
+            bad_checksum.validate_onion(),
+            Err(SocksError::InvalidDomainEncoding)
+        ));
+    }
+}